@@ -3,17 +3,29 @@
 //! See also <https://github.com/ethereum/devp2p/blob/master/README.md>
 
 use derive_more::Deref;
-use futures::{Sink, Stream};
+use futures::{future::BoxFuture, Sink, SinkExt, Stream};
 use reth_eth_wire::{
     capability::{SharedCapabilities, SharedCapability},
     multiplex::ProtocolStream,
     protocol::Protocol,
-    CanDisconnect,
+    CanDisconnect, DisconnectReason,
 };
 use reth_network_api::Direction;
-use reth_primitives::bytes::{Bytes, BytesMut};
+use reth_primitives::bytes::{BufMut, Bytes, BytesMut};
 use reth_rpc_types::PeerId;
-use std::{error, fmt, io, net::SocketAddr, pin::Pin};
+use std::{
+    collections::{HashMap, VecDeque},
+    error, fmt, io,
+    net::SocketAddr,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::PollSender;
 
 /// A trait that allows to offer additional RLPx-based application-level protocols when establishing
 /// a peer-to-peer connection.
@@ -66,13 +78,16 @@ pub trait ConnectionHandler: Send + Sync + 'static {
 
     /// Invoked when the RLPx connection was established.
     ///
-    /// The returned future should resolve when the connection should disconnect.
+    /// The returned future should resolve when the connection should disconnect. The returned
+    /// [`KeepAlive`] tells the driver how to handle an otherwise idle connection: whether it is
+    /// torn down right away, torn down once a deadline passes without traffic, or kept open
+    /// indefinitely.
     fn into_connection(
         self,
         direction: Direction,
         peer_id: PeerId,
         conn: Self::P2PConnection,
-    ) -> Option<Pin<Box<Self::Connection>>>;
+    ) -> Option<(Pin<Box<Self::Connection>>, KeepAlive)>;
 }
 
 /// What to do when a protocol is not supported by the remote.
@@ -85,6 +100,36 @@ pub enum OnNotSupported {
     Disconnect,
 }
 
+/// Idle keep-alive policy for an established [`Connection`], enforced by the driver task that
+/// owns the underlying [`ProxyProtocol`] stream.
+///
+/// The driver tracks the timestamp of the last message sent or received on the connection. If the
+/// policy is [`KeepAlive::Until`] and that deadline elapses without any new traffic resetting it,
+/// the driver resolves the connection future and triggers a disconnect. This lets bandwidth-light
+/// sub-protocols (e.g. discovery-style announce/ack) opt into automatic teardown of dormant peers
+/// without reinventing a timer, while long-lived sync protocols can opt into [`KeepAlive::Yes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepAlive {
+    /// Never time out due to inactivity; only an explicit disconnect or the connection future
+    /// resolving tears the connection down.
+    Yes,
+    /// Tear down the connection the next time the driver polls it, regardless of traffic.
+    No,
+    /// Tear down the connection if no traffic is seen before `Instant` elapses.
+    ///
+    /// Each time traffic resets the idle clock, the driver pushes this deadline forward by the
+    /// same idle duration the policy was originally constructed with.
+    Until(std::time::Instant),
+}
+
+impl KeepAlive {
+    /// Returns a [`KeepAlive::Until`] policy that expires `idle_for` from now if no traffic is
+    /// seen in the meantime.
+    pub fn until_idle_for(idle_for: std::time::Duration) -> Self {
+        KeepAlive::Until(std::time::Instant::now() + idle_for)
+    }
+}
+
 /// An established rlpx sub protocol connection as returned by [`ConnectionHandler`].
 pub trait Connection<
     StreamedType = dyn fmt::Debug,
@@ -126,17 +171,68 @@ where
     E: error::Error,
 {
 }
+/// Adds `offset` to `msg`'s leading message id byte, masking it relative to a capability's
+/// reserved suffix. The inverse of [`unmask_with_offset`].
+///
+/// The single implementation of this arithmetic; [`ProxyProtocol::relative_mask_msg_id`]'s
+/// default body and anything else masking relative to a [`SharedCapability`]'s offset should go
+/// through here rather than re-deriving it.
+fn mask_with_offset(offset: u8, mut msg: Bytes) -> Bytes {
+    if !msg.is_empty() {
+        let mut masked = BytesMut::from(&msg[..]);
+        masked[0] = masked[0].wrapping_add(offset);
+        msg = masked.freeze();
+    }
+    msg
+}
+
+/// Subtracts `offset` from `msg`'s leading masked message id byte. The inverse of
+/// [`mask_with_offset`].
+fn unmask_with_offset(offset: u8, mut msg: BytesMut) -> BytesMut {
+    if let Some(id) = msg.first_mut() {
+        *id = id.wrapping_sub(offset);
+    }
+    msg
+}
+
 /// Act as intermediary between p2p connection and protocol connection.
 pub trait ProxyProtocol {
     /// Shared capability assigned to proxy.
     fn shared_capability(&self) -> &SharedCapability;
 
+    /// Requests a graceful disconnect of the underlying p2p connection with the given devp2p
+    /// `reason` (e.g. [`DisconnectReason::TooManyPeers`], [`DisconnectReason::UselessPeer`]).
+    ///
+    /// Plumbed down to the shared [`reth_eth_wire::P2PStream`], so the corresponding `Disconnect`
+    /// message is emitted on the wire before the stream closes. Gives sub-protocols first-class,
+    /// observable shutdown semantics instead of opaquely dropping the sink.
+    fn disconnect(&mut self, reason: DisconnectReason) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Returns the devp2p reason the remote gave when it initiated the disconnect, if the
+    /// connection was closed that way.
+    ///
+    /// Since the [`Connection`] stream surfaces raw message payloads rather than a `Result`, the
+    /// driver consults this once the stream resolves to learn why, so a handler's connection
+    /// future can resolve with a [`DisconnectReason`] instead of an opaque closed stream.
+    fn disconnect_reason(&self) -> Option<DisconnectReason>;
+
     /// Returns the message with masked message ID.
     ///
     /// Mask the message ID of outgoing messages relative to suffix used for capability message
     /// IDs. [`reth_eth_wire::P2PStream`] further masks the message ID relative to the reserved
     /// p2p prefix. (todo: mask ID completely at this layer or sink BytesMut)
-    fn relative_mask_msg_id(&self, msg: BytesMut) -> Bytes;
+    ///
+    /// The default masks purely from this capability's offset via [`mask_with_offset`]; override
+    /// (as [`ProtocolStream`] does) when additional wire-level bookkeeping is needed.
+    fn relative_mask_msg_id(&self, msg: BytesMut) -> Bytes {
+        mask_with_offset(self.shared_capability().offset(), msg.freeze())
+    }
+
+    /// The inverse of [`ProxyProtocol::relative_mask_msg_id`]: removes this capability's offset
+    /// from an inbound masked message id.
+    fn relative_unmask_msg_id(&self, msg: BytesMut) -> BytesMut {
+        unmask_with_offset(self.shared_capability().offset(), msg)
+    }
 }
 
 impl ProxyProtocol for ProtocolStream {
@@ -144,11 +240,154 @@ impl ProxyProtocol for ProtocolStream {
         self.cap()
     }
 
+    fn disconnect(&mut self, reason: DisconnectReason) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            CanDisconnect::<Bytes>::disconnect(self, reason)
+                .await
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+        })
+    }
+
+    fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.remote_disconnect_reason()
+    }
+
     fn relative_mask_msg_id(&self, msg: BytesMut) -> Bytes {
         self.mask_msg_id(msg)
     }
 }
 
+/// Wraps an established [`Connection`] with a [`KeepAlive`] policy.
+///
+/// Enforces idle teardown itself: every poll that yields an item from the inner stream, or
+/// successfully sinks one, counts as traffic and resets the idle clock on a [`KeepAlive::Until`]
+/// policy. [`Self::poll_next`] ends the stream once the policy expires, racing a timer against
+/// the inner stream so a dormant connection is torn down even if the remote never sends again.
+pub struct IdleTimeoutConnection<C: ?Sized> {
+    conn: Pin<Box<C>>,
+    policy: KeepAlive,
+    /// The idle duration the current [`KeepAlive::Until`] deadline was computed from, so the
+    /// deadline can be pushed forward by the same amount when traffic resets it.
+    idle_for: Option<std::time::Duration>,
+    /// Timer racing the inner stream for the current [`KeepAlive::Until`] deadline. Armed lazily
+    /// on the first poll (so constructing `Self` doesn't require a Tokio runtime) and re-armed
+    /// whenever [`Self::mark_traffic`] pushes the deadline forward. Never armed under
+    /// [`KeepAlive::Yes`]/[`KeepAlive::No`], which don't need a timer to resolve.
+    deadline: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<C: ?Sized> fmt::Debug for IdleTimeoutConnection<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IdleTimeoutConnection")
+            .field("policy", &self.policy)
+            .field("idle_for", &self.idle_for)
+            .finish()
+    }
+}
+
+impl<C: ?Sized> IdleTimeoutConnection<C> {
+    /// Wraps `conn`, enforcing `policy` starting now.
+    pub fn new(conn: Pin<Box<C>>, policy: KeepAlive) -> Self {
+        let idle_for = match policy {
+            KeepAlive::Until(deadline) => {
+                Some(deadline.saturating_duration_since(std::time::Instant::now()))
+            }
+            KeepAlive::Yes | KeepAlive::No => None,
+        };
+        Self { conn, policy, idle_for, deadline: None }
+    }
+
+    /// Returns `true` if this connection's [`KeepAlive`] policy says it should be torn down now.
+    pub fn is_expired(&self) -> bool {
+        match self.policy {
+            KeepAlive::Yes => false,
+            KeepAlive::No => true,
+            KeepAlive::Until(deadline) => std::time::Instant::now() >= deadline,
+        }
+    }
+
+    /// Records traffic on the connection, pushing a [`KeepAlive::Until`] deadline forward by the
+    /// idle duration it was constructed or last reset with, and re-arming the deadline timer.
+    fn mark_traffic(&mut self) {
+        if let Some(idle_for) = self.idle_for {
+            let deadline = std::time::Instant::now() + idle_for;
+            self.policy = KeepAlive::Until(deadline);
+            self.deadline = Some(Box::pin(tokio::time::sleep_until(deadline.into())));
+        }
+    }
+
+    /// Ensures the deadline timer is armed for the current [`KeepAlive::Until`] policy, lazily
+    /// creating it on first use so construction doesn't require a Tokio runtime.
+    fn arm_deadline(&mut self) {
+        if self.deadline.is_none() {
+            if let KeepAlive::Until(deadline) = self.policy {
+                self.deadline = Some(Box::pin(tokio::time::sleep_until(deadline.into())));
+            }
+        }
+    }
+}
+
+impl<C: ?Sized, Item> Stream for IdleTimeoutConnection<C>
+where
+    C: Stream<Item = Item>,
+{
+    type Item = Item;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        if self.is_expired() {
+            return std::task::Poll::Ready(None)
+        }
+        self.arm_deadline();
+        if let Some(deadline) = self.deadline.as_mut() {
+            if std::future::Future::poll(deadline.as_mut(), cx).is_ready() {
+                return std::task::Poll::Ready(None)
+            }
+        }
+        let item = self.conn.as_mut().poll_next(cx);
+        if matches!(item, std::task::Poll::Ready(Some(_))) {
+            self.mark_traffic();
+        }
+        item
+    }
+}
+
+impl<C: ?Sized, SunkType> Sink<SunkType> for IdleTimeoutConnection<C>
+where
+    C: Sink<SunkType>,
+{
+    type Error = C::Error;
+
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.conn.as_mut().poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: SunkType) -> Result<(), Self::Error> {
+        self.conn.as_mut().start_send(item)?;
+        self.mark_traffic();
+        Ok(())
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.conn.as_mut().poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.conn.as_mut().poll_close(cx)
+    }
+}
+
 /// Convenience type setting associated type for [`ProtocolHandler`].
 pub type DynProtocolHandler = dyn ProtocolHandler<ConnectionHandler = DynConnectionHandler>;
 
@@ -181,11 +420,174 @@ impl IntoRlpxSubProtocol for RlpxSubProtocol {
     }
 }
 
+/// Records per-capability traffic and lifecycle metrics for RLPx sub-protocols.
+///
+/// Registered on [`RlpxSubProtocols`] via [`RlpxSubProtocols::set_recorder`]. Invoked from the
+/// [`ProxyProtocol`] boundary, where masked frames cross, and from connection
+/// establishment/teardown in [`RlpxSubProtocolDelegator::new`]/[`RlpxSubProtocolDelegator::run`],
+/// once negotiation with the remote is resolved. [`RlpxSubProtocols::on_incoming`] and
+/// [`RlpxSubProtocols::on_outgoing`] record nothing. An optional trait object so zero overhead is
+/// paid when no recorder is configured.
+pub trait SubProtocolRecorder: fmt::Debug + Send + Sync + 'static {
+    /// Invoked once per handler whose protocol was negotiated with the remote, i.e. when a
+    /// sub-protocol connection for `capability` is about to be established.
+    fn record_connected(&self, capability: &SharedCapability);
+
+    /// Invoked when a sub-protocol connection for `capability` is torn down, with the devp2p
+    /// reason if either side disconnected with one.
+    fn record_disconnected(&self, capability: &SharedCapability, reason: Option<DisconnectReason>);
+
+    /// Records an inbound message of `bytes` length for `capability`.
+    fn record_inbound(&self, capability: &SharedCapability, bytes: usize);
+
+    /// Records an outbound message of `bytes` length for `capability`.
+    fn record_outbound(&self, capability: &SharedCapability, bytes: usize);
+}
+
+/// Snapshot of the metrics recorded for a single capability.
+#[derive(Debug, Clone, Default)]
+pub struct SubProtocolMetrics {
+    /// Number of currently active connections negotiated for this capability.
+    pub active_connections: u64,
+    /// Number of inbound messages received.
+    pub messages_in: u64,
+    /// Number of outbound messages sent.
+    pub messages_out: u64,
+    /// Total inbound bytes received.
+    pub bytes_in: u64,
+    /// Total outbound bytes sent.
+    pub bytes_out: u64,
+    /// Disconnect reasons seen so far, and how many times each occurred.
+    pub disconnects: HashMap<DisconnectReason, u64>,
+}
+
+/// Built-in [`SubProtocolRecorder`] that aggregates per-capability metrics in memory behind a
+/// cheap-to-clone handle, so operators running multiple custom sub-protocols can see exactly
+/// which one is generating load.
+#[derive(Debug, Clone, Default)]
+pub struct SubProtocolMetricsRegistry {
+    metrics: Arc<parking_lot::RwLock<HashMap<String, SubProtocolMetrics>>>,
+}
+
+impl SubProtocolMetricsRegistry {
+    /// Returns a snapshot of the metrics recorded for the capability or protocol identified by
+    /// `name`, e.g. `"eth/68"`.
+    pub fn metrics_for(&self, name: &str) -> Option<SubProtocolMetrics> {
+        self.metrics.read().get(name).cloned()
+    }
+
+    /// Returns a snapshot of the metrics recorded for every capability seen so far.
+    pub fn metrics(&self) -> HashMap<String, SubProtocolMetrics> {
+        self.metrics.read().clone()
+    }
+}
+
+impl SubProtocolRecorder for SubProtocolMetricsRegistry {
+    fn record_connected(&self, capability: &SharedCapability) {
+        self.metrics.write().entry(capability.to_string()).or_default().active_connections += 1;
+    }
+
+    fn record_disconnected(&self, capability: &SharedCapability, reason: Option<DisconnectReason>) {
+        let mut metrics = self.metrics.write();
+        let entry = metrics.entry(capability.to_string()).or_default();
+        entry.active_connections = entry.active_connections.saturating_sub(1);
+        if let Some(reason) = reason {
+            *entry.disconnects.entry(reason).or_default() += 1;
+        }
+    }
+
+    fn record_inbound(&self, capability: &SharedCapability, bytes: usize) {
+        let mut metrics = self.metrics.write();
+        let entry = metrics.entry(capability.to_string()).or_default();
+        entry.messages_in += 1;
+        entry.bytes_in += bytes as u64;
+    }
+
+    fn record_outbound(&self, capability: &SharedCapability, bytes: usize) {
+        let mut metrics = self.metrics.write();
+        let entry = metrics.entry(capability.to_string()).or_default();
+        entry.messages_out += 1;
+        entry.bytes_out += bytes as u64;
+    }
+}
+
+/// Wraps a [`ProxyProtocol`] implementation (typically a [`ProtocolStream`]) to invoke a
+/// [`SubProtocolRecorder`] at the boundary where masked frames cross.
+#[derive(Debug)]
+pub struct RecordingProxyProtocol<P> {
+    inner: P,
+    recorder: Arc<dyn SubProtocolRecorder>,
+}
+
+impl<P> RecordingProxyProtocol<P> {
+    /// Wraps `inner`, recording traffic and disconnects on `recorder`.
+    pub fn new(inner: P, recorder: Arc<dyn SubProtocolRecorder>) -> Self {
+        Self { inner, recorder }
+    }
+}
+
+impl<P> ProxyProtocol for RecordingProxyProtocol<P>
+where
+    P: ProxyProtocol,
+{
+    fn shared_capability(&self) -> &SharedCapability {
+        self.inner.shared_capability()
+    }
+
+    fn disconnect(&mut self, reason: DisconnectReason) -> BoxFuture<'_, io::Result<()>> {
+        self.recorder.record_disconnected(self.inner.shared_capability(), Some(reason));
+        self.inner.disconnect(reason)
+    }
+
+    fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.inner.disconnect_reason()
+    }
+
+    fn relative_mask_msg_id(&self, msg: BytesMut) -> Bytes {
+        let bytes = msg.len();
+        self.recorder.record_outbound(self.inner.shared_capability(), bytes);
+        self.inner.relative_mask_msg_id(msg)
+    }
+}
+
+impl<P, Item> Stream for RecordingProxyProtocol<P>
+where
+    P: ProxyProtocol + Stream<Item = Item> + Unpin,
+    Item: AsRef<[u8]>,
+{
+    type Item = Item;
+
+    /// Records an inbound message crossing the [`ProxyProtocol`] boundary before passing it
+    /// through, mirroring how [`ProxyProtocol::relative_mask_msg_id`] records outbound ones.
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_next(cx);
+        if let std::task::Poll::Ready(Some(item)) = &poll {
+            this.recorder.record_inbound(this.inner.shared_capability(), item.as_ref().len());
+        }
+        poll
+    }
+}
+
 /// Additional RLPx-based sub-protocols.
-#[derive(Debug, Default, Deref)]
+#[derive(Debug, Default)]
 pub struct RlpxSubProtocols {
     /// All extra protocols
     protocols: Vec<RlpxSubProtocol>,
+    /// Optional recorder invoked at the [`ProxyProtocol`] boundary and from connection
+    /// establishment/teardown, so zero overhead is paid when unset.
+    recorder: Option<Arc<dyn SubProtocolRecorder>>,
+}
+
+impl Deref for RlpxSubProtocols {
+    type Target = Vec<RlpxSubProtocol>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.protocols
+    }
 }
 
 impl RlpxSubProtocols {
@@ -194,8 +596,26 @@ impl RlpxSubProtocols {
         self.protocols.push(protocol.into_rlpx_sub_protocol());
     }
 
+    /// Configures the [`SubProtocolRecorder`] invoked for every protocol pushed so far and
+    /// pushed hereafter.
+    pub fn set_recorder(&mut self, recorder: impl SubProtocolRecorder) {
+        self.recorder = Some(Arc::new(recorder));
+    }
+
+    /// Returns the configured [`SubProtocolRecorder`], if any, e.g. to pass on to a
+    /// [`RlpxSubProtocolDelegator`] so it can record lifecycle metrics once negotiation against
+    /// the remote's [`SharedCapabilities`] is actually resolved.
+    pub(crate) fn recorder(&self) -> Option<Arc<dyn SubProtocolRecorder>> {
+        self.recorder.clone()
+    }
+
     /// Returns all additional protocol handlers that should be announced to the remote during the
     /// Rlpx handshake on an incoming connection.
+    ///
+    /// Does not itself record connection lifecycle metrics: at this point the handler has only
+    /// been offered, not negotiated with the remote, so recording a connection here would count
+    /// it even when the remote never agrees to the capability. See
+    /// [`RlpxSubProtocolDelegator::new`], which records once negotiation is resolved.
     pub(crate) fn on_incoming(&self, socket_addr: SocketAddr) -> RlpxSubProtocolHandlers {
         RlpxSubProtocolHandlers(
             self.protocols
@@ -207,6 +627,9 @@ impl RlpxSubProtocols {
 
     /// Returns all additional protocol handlers that should be announced to the remote during the
     /// Rlpx handshake on an outgoing connection.
+    ///
+    /// See the note on [`RlpxSubProtocols::on_incoming`]: connection lifecycle metrics aren't
+    /// recorded here, only once negotiation is resolved in [`RlpxSubProtocolDelegator::new`].
     pub(crate) fn on_outgoing(
         &self,
         socket_addr: SocketAddr,
@@ -245,3 +668,921 @@ impl DerefMut for RlpxSubProtocolHandlers {
         &mut self.0
     }
 }
+
+/// A unique identifier for an in-flight request on a [`RequestResponseConnection`].
+///
+/// Assigned by the requester and echoed back by the remote so the reply can be correlated with
+/// the [`oneshot`] channel awaiting it.
+pub type RequestId = u16;
+
+/// Generates monotonically increasing [`RequestId`]s for outgoing requests on a single
+/// connection.
+#[derive(Debug, Default)]
+struct RequestIdGenerator(AtomicU16);
+
+impl RequestIdGenerator {
+    /// Returns the next [`RequestId`] not already present in `pending`, or `None` if all 65536
+    /// ids are currently in flight.
+    ///
+    /// Expects `pending`'s lock to already be held by the caller, so the check-and-reserve below
+    /// is atomic with respect to other callers: the 16-bit id space can wrap on a long-lived,
+    /// high-throughput connection, and reusing an id that's still outstanding would silently
+    /// overwrite (and orphan) the earlier caller's [`ResponseSender`].
+    fn next(&self, pending: &HashMap<RequestId, ResponseSender>) -> Option<RequestId> {
+        let start = self.0.load(Ordering::Relaxed);
+        let mut id = start;
+        loop {
+            if !pending.contains_key(&id) {
+                self.0.store(id.wrapping_add(1), Ordering::Relaxed);
+                return Some(id)
+            }
+            id = id.wrapping_add(1);
+            if id == start {
+                return None
+            }
+        }
+    }
+}
+
+/// The priority of an outbound message queued on a [`RequestResponseConnection`].
+///
+/// The send loop driving the underlying sink always selects the highest-priority non-empty
+/// queue, so a large, low-priority bulk transfer (e.g. snapshot bytes) never blocks small
+/// high-priority control frames queued on the same sub-protocol stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RequestPriority {
+    /// Small, latency sensitive frames, e.g. pings or request headers.
+    High,
+    /// Default priority for ordinary requests.
+    Normal,
+    /// High-throughput, latency insensitive transfers, e.g. snapshot sync.
+    Low,
+}
+
+impl RequestPriority {
+    /// All priority classes, ordered from highest to lowest.
+    const ALL: [RequestPriority; 3] =
+        [RequestPriority::High, RequestPriority::Normal, RequestPriority::Low];
+
+    /// Returns the numeric representation of this priority, with `0` being the highest.
+    pub fn as_u8(&self) -> u8 {
+        *self as u8
+    }
+}
+
+impl Default for RequestPriority {
+    fn default() -> Self {
+        RequestPriority::Normal
+    }
+}
+
+/// Errors produced by the request/response subsystem of a [`RequestResponseConnection`].
+#[derive(Debug, thiserror::Error)]
+pub enum RequestResponseError {
+    /// The connection was torn down before a response to the request was received.
+    #[error("connection closed before a response was received")]
+    ConnectionClosed,
+    /// The outbound request could not be queued because the send loop has already shut down.
+    #[error("failed to queue outbound request, send loop is no longer running")]
+    SendLoopClosed,
+    /// All 65536 request ids are currently in flight on this connection.
+    ///
+    /// In practice this requires 65536 simultaneously outstanding requests and should never be
+    /// hit; surfaced as an error rather than reusing an in-flight id and silently orphaning it.
+    #[error("all request ids are in flight on this connection")]
+    IdSpaceExhausted,
+}
+
+/// A message queued for delivery to the remote, tagged with the [`RequestPriority`] that
+/// determines when the send loop flushes it relative to other queued messages.
+#[derive(Debug)]
+struct QueuedMessage {
+    priority: RequestPriority,
+    msg: Bytes,
+}
+
+/// Sends the resolved response (or error) for one in-flight request back to the
+/// [`RequestResponseHandle::request`] future awaiting it.
+type ResponseSender = oneshot::Sender<Result<Bytes, RequestResponseError>>;
+
+/// Table of requests awaiting a response, keyed by the [`RequestId`] assigned when they were
+/// sent.
+type PendingRequests = Arc<parking_lot::Mutex<HashMap<RequestId, ResponseSender>>>;
+
+/// Client-side handle for sending prioritized, correlated requests on a
+/// [`RequestResponseConnection`] and awaiting their response.
+#[derive(Debug, Clone)]
+pub struct RequestResponseHandle {
+    to_send: mpsc::UnboundedSender<QueuedMessage>,
+    pending: PendingRequests,
+    ids: Arc<RequestIdGenerator>,
+}
+
+impl RequestResponseHandle {
+    /// Queues `msg` as a request frame carrying a fresh [`RequestId`] and the message
+    /// discriminant `msg_id` for delivery at the given `priority`, and returns a future that
+    /// resolves with the response once the remote replies with the correlated [`RequestId`].
+    ///
+    /// Resolves with [`RequestResponseError::ConnectionClosed`] if the connection is torn down
+    /// before a response arrives.
+    pub fn request(
+        &self,
+        msg_id: u8,
+        msg: Bytes,
+        priority: RequestPriority,
+    ) -> impl std::future::Future<Output = Result<Bytes, RequestResponseError>> {
+        // Reserving the id and inserting its `ResponseSender` while holding `pending`'s lock
+        // keeps the two atomic, so a concurrent caller can never observe (or reuse) the same id.
+        let reserved = {
+            let mut pending = self.pending.lock();
+            self.ids.next(&pending).map(|id| {
+                let (tx, rx) = oneshot::channel();
+                pending.insert(id, tx);
+                (id, rx)
+            })
+        };
+
+        let queued_ok = reserved.as_ref().map(|(id, _)| {
+            let queued = QueuedMessage { priority, msg: encode_request_frame(*id, msg_id, msg) };
+            self.to_send.send(queued).is_ok()
+        });
+        let pending = self.pending.clone();
+
+        async move {
+            let Some((id, rx)) = reserved else {
+                return Err(RequestResponseError::IdSpaceExhausted)
+            };
+            if queued_ok != Some(true) {
+                pending.lock().remove(&id);
+                return Err(RequestResponseError::SendLoopClosed)
+            }
+            rx.await.unwrap_or(Err(RequestResponseError::ConnectionClosed))
+        }
+    }
+}
+
+/// An inbound request handler: takes the request payload and resolves with the response payload
+/// to send back.
+type RequestHandlerFn = dyn Fn(Bytes) -> BoxFuture<'static, Bytes> + Send + Sync;
+
+/// Registry of request handlers for the server-side of a [`RequestResponseConnection`], keyed by
+/// the message discriminant of the inbound request.
+#[derive(Default)]
+pub struct RequestHandlerRegistry {
+    handlers: HashMap<u8, Box<RequestHandlerFn>>,
+}
+
+impl RequestHandlerRegistry {
+    /// Registers `handler` to answer requests whose message discriminant is `msg_id`.
+    ///
+    /// Replaces any handler previously registered for the same discriminant.
+    pub fn insert<F, Fut>(&mut self, msg_id: u8, handler: F)
+    where
+        F: Fn(Bytes) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Bytes> + Send + 'static,
+    {
+        self.handlers.insert(msg_id, Box::new(move |msg| Box::pin(handler(msg))));
+    }
+
+    /// Returns the handler registered for `msg_id`, if any.
+    fn get(&self, msg_id: u8) -> Option<&RequestHandlerFn> {
+        self.handlers.get(&msg_id).map(|handler| handler.as_ref())
+    }
+}
+
+impl fmt::Debug for RequestHandlerRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestHandlerRegistry")
+            .field("registered", &self.handlers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// Maximum number of messages flushed from a single priority queue before the send loop
+/// re-checks priorities, so a large low-priority backlog cannot monopolize the sink even within
+/// its own turn.
+const PRIORITY_QUEUE_CHUNK_SIZE: usize = 16;
+
+/// Drives the outbound side of a [`RequestResponseConnection`]'s underlying sink.
+///
+/// On each turn, the loop selects the highest-priority non-empty queue and flushes up to
+/// [`PRIORITY_QUEUE_CHUNK_SIZE`] messages from it before re-checking priorities. Ordering within
+/// a single priority class is preserved. When the loop exits, every pending `oneshot` in
+/// `pending` is dropped with [`RequestResponseError::ConnectionClosed`].
+pub struct PrioritySendLoop<Si> {
+    sink: Pin<Box<Si>>,
+    receiver: mpsc::UnboundedReceiver<QueuedMessage>,
+    pending: PendingRequests,
+    queues: HashMap<RequestPriority, VecDeque<QueuedMessage>>,
+}
+
+impl<Si> PrioritySendLoop<Si>
+where
+    Si: Sink<Bytes> + Send,
+{
+    /// Runs the send loop to completion, i.e. until the outbound channel closes or the sink
+    /// errors.
+    pub async fn run(mut self) {
+        loop {
+            // Wait for a message only if every queue is empty, then pull everything else already
+            // queued into its priority bucket without blocking.
+            let has_work = self.queues.values().any(|queue| !queue.is_empty());
+            if !has_work {
+                match self.receiver.recv().await {
+                    Some(queued) => {
+                        self.queues.entry(queued.priority).or_default().push_back(queued)
+                    }
+                    None => break,
+                }
+            }
+            while let Ok(queued) = self.receiver.try_recv() {
+                self.queues.entry(queued.priority).or_default().push_back(queued);
+            }
+
+            for priority in RequestPriority::ALL {
+                let Some(queue) = self.queues.get_mut(&priority) else { continue };
+                let mut sent = 0;
+                while sent < PRIORITY_QUEUE_CHUNK_SIZE {
+                    let Some(queued) = queue.pop_front() else { break };
+                    if self.sink.send(queued.msg).await.is_err() {
+                        self.fail_all_pending();
+                        return
+                    }
+                    sent += 1;
+                }
+                if sent > 0 {
+                    break
+                }
+            }
+        }
+
+        self.fail_all_pending();
+    }
+
+    /// Resolves every still-pending request with [`RequestResponseError::ConnectionClosed`].
+    fn fail_all_pending(&self) {
+        for (_, tx) in self.pending.lock().drain() {
+            let _ = tx.send(Err(RequestResponseError::ConnectionClosed));
+        }
+    }
+}
+
+/// Wire tag marking a [`RequestResponseConnection`] frame as a request awaiting a reply.
+const REQUEST_FRAME_TAG: u8 = 0;
+/// Wire tag marking a [`RequestResponseConnection`] frame as the reply to a request.
+const RESPONSE_FRAME_TAG: u8 = 1;
+
+/// Encodes `payload` as an outbound request frame for `id`, tagged with the message discriminant
+/// `msg_id` so the remote's [`RequestHandlerRegistry`] can dispatch it.
+fn encode_request_frame(id: RequestId, msg_id: u8, payload: Bytes) -> Bytes {
+    let mut buf = BytesMut::with_capacity(4 + payload.len());
+    buf.put_u8(REQUEST_FRAME_TAG);
+    buf.put_u16(id);
+    buf.put_u8(msg_id);
+    buf.extend_from_slice(&payload);
+    buf.freeze()
+}
+
+/// Encodes `payload` as the reply frame correlated to request `id`.
+fn encode_response_frame(id: RequestId, payload: Bytes) -> Bytes {
+    let mut buf = BytesMut::with_capacity(3 + payload.len());
+    buf.put_u8(RESPONSE_FRAME_TAG);
+    buf.put_u16(id);
+    buf.extend_from_slice(&payload);
+    buf.freeze()
+}
+
+/// A [`RequestResponseConnection`] frame decoded off the wire.
+#[derive(Debug, PartialEq, Eq)]
+enum DecodedFrame {
+    /// An inbound request the remote wants this side's [`RequestHandlerRegistry`] to answer.
+    Request { id: RequestId, msg_id: u8, payload: Bytes },
+    /// An inbound reply correlated to a request this side previously sent.
+    Response { id: RequestId, payload: Bytes },
+}
+
+/// Decodes a raw inbound frame, returning `None` if it's malformed (too short for its tag).
+fn decode_frame(mut frame: BytesMut) -> Option<DecodedFrame> {
+    if frame.is_empty() {
+        return None
+    }
+    let tag = frame.split_to(1)[0];
+    match tag {
+        REQUEST_FRAME_TAG if frame.len() >= 3 => {
+            let id = u16::from_be_bytes([frame[0], frame[1]]);
+            let msg_id = frame[2];
+            let payload = frame.split_off(3).freeze();
+            Some(DecodedFrame::Request { id, msg_id, payload })
+        }
+        RESPONSE_FRAME_TAG if frame.len() >= 2 => {
+            let id = u16::from_be_bytes([frame[0], frame[1]]);
+            let payload = frame.split_off(2).freeze();
+            Some(DecodedFrame::Response { id, payload })
+        }
+        _ => None,
+    }
+}
+
+/// The established [`Connection`] half of the request/response subsystem.
+///
+/// Wraps the inbound frame stream paired with a [`RequestResponseHandle`]/[`PrioritySendLoop`] by
+/// [`split_request_response`]: reply frames complete the correlated `oneshot` in `pending`, and
+/// request frames are dispatched to `registry`, with the handler's reply queued back out through
+/// the same send loop `to_send` feeds.
+///
+/// Polling this as a [`Stream`] is what actually drives the dispatch; it yields `Some(())` once
+/// per processed inbound frame, queues any handler replies that have completed on every poll
+/// without yielding for them, and yields `None` once the underlying stream closes.
+pub struct RequestResponseConnection<St> {
+    inbound: Pin<Box<St>>,
+    pending: PendingRequests,
+    registry: Arc<RequestHandlerRegistry>,
+    to_send: mpsc::UnboundedSender<QueuedMessage>,
+    replies: futures::stream::FuturesUnordered<BoxFuture<'static, (RequestId, Bytes)>>,
+}
+
+impl<St> RequestResponseConnection<St> {
+    /// Wraps `inbound`, completing `pending` for replies and dispatching `registry` for requests,
+    /// queuing outbound replies onto `to_send`.
+    fn new(
+        inbound: Pin<Box<St>>,
+        pending: PendingRequests,
+        registry: Arc<RequestHandlerRegistry>,
+        to_send: mpsc::UnboundedSender<QueuedMessage>,
+    ) -> Self {
+        Self { inbound, pending, registry, to_send, replies: Default::default() }
+    }
+}
+
+impl<St> Stream for RequestResponseConnection<St>
+where
+    St: Stream<Item = BytesMut> + Unpin,
+{
+    type Item = ();
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        use futures::StreamExt;
+
+        // Flush replies from dispatched request handlers that finished since the last poll.
+        while let std::task::Poll::Ready(Some((id, payload))) = self.replies.poll_next_unpin(cx) {
+            let msg = QueuedMessage {
+                priority: RequestPriority::Normal,
+                msg: encode_response_frame(id, payload),
+            };
+            let _ = self.to_send.send(msg);
+        }
+
+        match self.inbound.as_mut().poll_next(cx) {
+            std::task::Poll::Ready(Some(frame)) => {
+                match decode_frame(frame) {
+                    Some(DecodedFrame::Response { id, payload }) => {
+                        if let Some(tx) = self.pending.lock().remove(&id) {
+                            let _ = tx.send(Ok(payload));
+                        }
+                    }
+                    Some(DecodedFrame::Request { id, msg_id, payload }) => {
+                        if let Some(handler) = self.registry.get(msg_id) {
+                            let reply = handler(payload);
+                            self.replies.push(Box::pin(async move { (id, reply.await) }));
+                        }
+                    }
+                    None => {}
+                }
+                std::task::Poll::Ready(Some(()))
+            }
+            std::task::Poll::Ready(None) => std::task::Poll::Ready(None),
+            std::task::Poll::Pending => std::task::Poll::Pending,
+        }
+    }
+}
+
+/// Splits `conn` into the client [`RequestResponseHandle`], the [`PrioritySendLoop`] driving its
+/// outbound sink, and the [`RequestResponseConnection`] driving its inbound stream and dispatching
+/// `registry` for server-side requests.
+///
+/// Callers are expected to `tokio::spawn` the returned [`PrioritySendLoop::run`] and poll the
+/// [`RequestResponseConnection`] to drive inbound dispatch. It implements [`Stream`] only, not
+/// [`Sink`], so it is not itself a [`Connection`].
+pub fn split_request_response<Conn>(
+    conn: Conn,
+    registry: RequestHandlerRegistry,
+) -> (
+    RequestResponseHandle,
+    PrioritySendLoop<futures::stream::SplitSink<Conn, Bytes>>,
+    RequestResponseConnection<futures::stream::SplitStream<Conn>>,
+)
+where
+    Conn: Stream<Item = BytesMut> + Sink<Bytes> + Send + Unpin + 'static,
+{
+    use futures::StreamExt;
+
+    let (sink, stream) = conn.split();
+    let (to_send, receiver) = mpsc::unbounded_channel();
+    let pending: PendingRequests = Arc::new(parking_lot::Mutex::new(HashMap::new()));
+    let ids = Arc::new(RequestIdGenerator::default());
+    let registry = Arc::new(registry);
+
+    let handle = RequestResponseHandle { to_send: to_send.clone(), pending: pending.clone(), ids };
+    let send_loop = PrioritySendLoop {
+        sink: Box::pin(sink),
+        receiver,
+        pending: pending.clone(),
+        queues: HashMap::new(),
+    };
+    let connection = RequestResponseConnection::new(Box::pin(stream), pending, registry, to_send);
+
+    (handle, send_loop, connection)
+}
+
+/// Returns the masked message id range reserved for `capability` on a p2p stream shared with
+/// other capabilities, i.e. `capability.offset()..capability.offset() + capability.num_messages()`.
+fn capability_id_range(capability: &SharedCapability) -> std::ops::Range<u8> {
+    capability.offset()..(capability.offset() + capability.num_messages())
+}
+
+/// A capability-scoped [`P2PConnection`] multiplexed over one shared transport by a
+/// [`RlpxSubProtocolDelegator`], passed to [`ConnectionHandler::into_connection`] in place of a
+/// dedicated [`ProtocolStream`].
+///
+/// Inbound frames destined for this capability arrive from the delegator's demux loop over an
+/// internal channel; outbound frames are queued back to the delegator, which remasks them with
+/// this capability's offset before writing to the shared stream.
+///
+/// The outbound queue is bounded: [`Sink::poll_ready`]/[`Sink::poll_flush`] report the delegator's
+/// actual queue depth via [`PollSender`] rather than always returning ready, so a delegate
+/// producing outbound frames faster than the shared transport can drain applies backpressure
+/// instead of growing the queue without bound.
+pub struct CapabilityConnection {
+    capability: SharedCapability,
+    inbound: mpsc::UnboundedReceiver<BytesMut>,
+    outbound: PollSender<(SharedCapability, Bytes)>,
+    disconnect: mpsc::UnboundedSender<(SharedCapability, DisconnectReason)>,
+    disconnect_reason: Arc<parking_lot::Mutex<Option<DisconnectReason>>>,
+}
+
+impl Stream for CapabilityConnection {
+    type Item = BytesMut;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.inbound.poll_recv(cx)
+    }
+}
+
+impl Sink<Bytes> for CapabilityConnection {
+    type Error = io::Error;
+
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.outbound
+            .poll_ready_unpin(cx)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "delegator shut down"))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
+        let capability = self.capability.clone();
+        self.outbound
+            .start_send_unpin((capability, item))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "delegator shut down"))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.outbound
+            .poll_flush_unpin(cx)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "delegator shut down"))
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.outbound
+            .poll_close_unpin(cx)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "delegator shut down"))
+    }
+}
+
+impl ProxyProtocol for CapabilityConnection {
+    fn shared_capability(&self) -> &SharedCapability {
+        &self.capability
+    }
+
+    fn disconnect(&mut self, reason: DisconnectReason) -> BoxFuture<'_, io::Result<()>> {
+        let sent = self.disconnect.send((self.capability.clone(), reason)).is_ok();
+        Box::pin(async move {
+            if sent {
+                Ok(())
+            } else {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "delegator shut down"))
+            }
+        })
+    }
+
+    fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        *self.disconnect_reason.lock()
+    }
+}
+
+/// Convenience type setting associated types for a [`ConnectionHandler`] that can be delegated by
+/// a [`RlpxSubProtocolDelegator`], i.e. whose `P2PConnection` is a capability-scoped view over one
+/// shared transport rather than a dedicated [`ProtocolStream`].
+pub type DynDelegatedConnectionHandler =
+    dyn ConnectionHandler<Connection = dyn Connection, P2PConnection = CapabilityConnection>;
+
+/// Demultiplexes one established p2p connection across several negotiated sub-protocol
+/// [`Connection`]s that share it, instead of each allocating a separate TCP/RLPx session.
+///
+/// Owns the shared transport and polls it directly: inbound frames are routed to the delegate
+/// whose [`SharedCapability`] reserved id range (see [`capability_id_range`]) contains the masked
+/// message id, and outbound frames queued by any delegate are remasked with that delegate's own
+/// capability offset before being written back. [`ConnectionHandler::on_unsupported_by_peer`] and
+/// [`ConnectionHandler::into_connection`] are dispatched uniformly across every delegate when
+/// constructed, and once the shared transport closes every delegate is torn down together.
+pub struct RlpxSubProtocolDelegator<Conn> {
+    shared: Pin<Box<Conn>>,
+    routes: Vec<(SharedCapability, mpsc::UnboundedSender<BytesMut>)>,
+    outbound_tx: mpsc::Sender<(SharedCapability, Bytes)>,
+    outbound_rx: mpsc::Receiver<(SharedCapability, Bytes)>,
+    disconnect_rx: mpsc::UnboundedReceiver<(SharedCapability, DisconnectReason)>,
+    disconnect_tx: mpsc::UnboundedSender<(SharedCapability, DisconnectReason)>,
+    disconnect_reason: Arc<parking_lot::Mutex<Option<DisconnectReason>>>,
+    /// Invoked once per route when negotiation resolves in [`RlpxSubProtocolDelegator::new`], and
+    /// once per route when [`RlpxSubProtocolDelegator::run`] tears the shared transport down. Not
+    /// invoked for handlers whose protocol the remote didn't support, since those never count as
+    /// connected in the first place.
+    recorder: Option<Arc<dyn SubProtocolRecorder>>,
+}
+
+/// Bounded capacity of the queue between a [`CapabilityConnection`]'s outbound [`Sink`] and the
+/// [`RlpxSubProtocolDelegator`]'s mux loop, so a delegate producing frames faster than the shared
+/// transport can drain applies backpressure instead of growing the queue without bound.
+const DELEGATOR_OUTBOUND_QUEUE_DEPTH: usize = 64;
+
+impl<Conn> RlpxSubProtocolDelegator<Conn>
+where
+    Conn: P2PConnection + Unpin,
+{
+    /// Negotiates `handlers` against `supported`, invoking
+    /// [`ConnectionHandler::on_unsupported_by_peer`] for every handler whose protocol wasn't
+    /// shared by the remote, and [`ConnectionHandler::into_connection`] uniformly for every
+    /// handler that matched a negotiated [`SharedCapability`].
+    ///
+    /// Returns the delegator and the established `(Connection, KeepAlive)` pairs, each wrapped in
+    /// an [`IdleTimeoutConnection`] that enforces its own `KeepAlive` policy. Call
+    /// [`RlpxSubProtocolDelegator::run`] to drive the demux/mux loop.
+    ///
+    /// `recorder`, if set, is invoked with [`SubProtocolRecorder::record_connected`] once per
+    /// handler that matched a negotiated capability. Pass [`RlpxSubProtocols::recorder`]. Handlers
+    /// whose protocol the remote didn't support are never recorded as connected, so
+    /// [`RlpxSubProtocolDelegator::run`] only ever decrements what was actually incremented here.
+    pub(crate) fn new(
+        handlers: Vec<Box<dyn DynDelegatedConnectionHandler>>,
+        supported: &SharedCapabilities,
+        direction: Direction,
+        peer_id: PeerId,
+        shared: Pin<Box<Conn>>,
+        recorder: Option<Arc<dyn SubProtocolRecorder>>,
+    ) -> (Self, Vec<(Pin<Box<dyn Connection>>, KeepAlive)>) {
+        let (outbound_tx, outbound_rx) = mpsc::channel(DELEGATOR_OUTBOUND_QUEUE_DEPTH);
+        let (disconnect_tx, disconnect_rx) = mpsc::unbounded_channel();
+        let disconnect_reason = Arc::new(parking_lot::Mutex::new(None));
+
+        let mut routes = Vec::new();
+        let mut connections = Vec::new();
+        for handler in handlers {
+            let protocol = handler.protocol();
+            let Some(capability) =
+                supported.iter().find(|cap| cap.protocol() == &protocol).cloned()
+            else {
+                handler.on_unsupported_by_peer(supported, direction, peer_id);
+                continue
+            };
+
+            if let Some(recorder) = &recorder {
+                recorder.record_connected(&capability);
+            }
+
+            let (inbound_tx, inbound_rx) = mpsc::unbounded_channel();
+            routes.push((capability.clone(), inbound_tx));
+
+            let conn = CapabilityConnection {
+                capability,
+                inbound: inbound_rx,
+                outbound: PollSender::new(outbound_tx.clone()),
+                disconnect: disconnect_tx.clone(),
+                disconnect_reason: disconnect_reason.clone(),
+            };
+            if let Some((conn, policy)) = handler.into_connection(direction, peer_id, conn) {
+                let conn: Pin<Box<dyn Connection>> =
+                    Box::pin(IdleTimeoutConnection::new(conn, policy));
+                connections.push((conn, policy));
+            }
+        }
+
+        (
+            Self {
+                shared,
+                routes,
+                outbound_tx,
+                outbound_rx,
+                disconnect_rx,
+                disconnect_tx,
+                disconnect_reason,
+                recorder,
+            },
+            connections,
+        )
+    }
+
+    /// Drives the demux/mux loop until the shared transport closes, at which point every
+    /// delegate's inbound channel is dropped, ending its [`Connection`] stream.
+    pub(crate) async fn run(mut self) {
+        use futures::StreamExt;
+
+        loop {
+            tokio::select! {
+                frame = self.shared.next() => {
+                    let Some(mut frame) = frame else { break };
+                    let Some(id) = frame.first().copied() else { continue };
+                    if let Some((capability, route)) =
+                        self.routes.iter().find(|(cap, _)| capability_id_range(cap).contains(&id))
+                    {
+                        if let Some(recorder) = &self.recorder {
+                            recorder.record_inbound(capability, frame.len());
+                        }
+                        frame = unmask_with_offset(capability.offset(), frame);
+                        let _ = route.send(frame);
+                    }
+                }
+                Some((capability, msg)) = self.outbound_rx.recv() => {
+                    if let Some(recorder) = &self.recorder {
+                        recorder.record_outbound(&capability, msg.len());
+                    }
+                    let masked = mask_with_offset(capability.offset(), msg);
+                    if futures::SinkExt::send(&mut self.shared, masked).await.is_err() {
+                        break
+                    }
+                }
+                Some((_capability, reason)) = self.disconnect_rx.recv() => {
+                    let _ =
+                        CanDisconnect::<Bytes>::disconnect(self.shared.as_mut().get_mut(), reason)
+                            .await;
+                    break
+                }
+            }
+        }
+
+        let reason = self.shared.disconnect_reason();
+        *self.disconnect_reason.lock() = reason;
+        if let Some(recorder) = &self.recorder {
+            for (capability, _) in &self.routes {
+                recorder.record_disconnected(capability, reason);
+            }
+        }
+        // Dropping `self.routes` drops every delegate's inbound sender, ending its stream.
+    }
+}
+
+#[cfg(test)]
+mod idle_timeout_connection_tests {
+    use super::*;
+
+    #[test]
+    fn keep_alive_yes_never_expires() {
+        let conn =
+            IdleTimeoutConnection::new(Box::pin(futures::stream::empty::<()>()), KeepAlive::Yes);
+        assert!(!conn.is_expired());
+    }
+
+    #[test]
+    fn keep_alive_no_is_always_expired() {
+        let conn =
+            IdleTimeoutConnection::new(Box::pin(futures::stream::empty::<()>()), KeepAlive::No);
+        assert!(conn.is_expired());
+    }
+
+    #[test]
+    fn keep_alive_until_expires_after_deadline() {
+        let conn = IdleTimeoutConnection::new(
+            Box::pin(futures::stream::empty::<()>()),
+            KeepAlive::Until(std::time::Instant::now() - std::time::Duration::from_secs(1)),
+        );
+        assert!(conn.is_expired());
+    }
+
+    #[tokio::test]
+    async fn traffic_resets_the_idle_deadline() {
+        use futures::StreamExt;
+
+        let idle_for = std::time::Duration::from_secs(30);
+        let mut conn = IdleTimeoutConnection::new(
+            Box::pin(futures::stream::iter([(), ()])),
+            KeepAlive::until_idle_for(idle_for),
+        );
+        let KeepAlive::Until(initial_deadline) = conn.policy else { unreachable!() };
+
+        assert_eq!(conn.next().await, Some(()));
+
+        let KeepAlive::Until(deadline_after_traffic) = conn.policy else { unreachable!() };
+        assert!(deadline_after_traffic >= initial_deadline);
+        assert!(!conn.is_expired());
+    }
+
+    #[tokio::test]
+    async fn poll_next_ends_the_stream_once_expired() {
+        use futures::StreamExt;
+
+        // An inner stream that never completes on its own; only expiry should end it.
+        let mut conn = IdleTimeoutConnection::new(
+            Box::pin(futures::stream::pending::<()>()),
+            KeepAlive::No,
+        );
+        assert_eq!(conn.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn poll_next_ends_the_stream_once_the_deadline_elapses() {
+        use futures::StreamExt;
+
+        let mut conn = IdleTimeoutConnection::new(
+            Box::pin(futures::stream::pending::<()>()),
+            KeepAlive::until_idle_for(std::time::Duration::from_millis(10)),
+        );
+        assert_eq!(conn.next().await, None);
+    }
+}
+
+#[cfg(test)]
+mod capability_masking_tests {
+    use super::*;
+
+    #[test]
+    fn mask_and_unmask_round_trip() {
+        let masked = mask_with_offset(16, Bytes::from_static(&[3, 0xaa, 0xbb]));
+        assert_eq!(masked, Bytes::from_static(&[19, 0xaa, 0xbb]));
+
+        let unmasked = unmask_with_offset(16, BytesMut::from(&masked[..]));
+        assert_eq!(unmasked, BytesMut::from(&[3u8, 0xaa, 0xbb][..]));
+    }
+
+    #[test]
+    fn mask_with_offset_leaves_empty_message_untouched() {
+        assert_eq!(mask_with_offset(16, Bytes::new()), Bytes::new());
+        assert_eq!(unmask_with_offset(16, BytesMut::new()), BytesMut::new());
+    }
+}
+
+#[cfg(test)]
+mod request_response_tests {
+    use super::*;
+
+    #[test]
+    fn request_response_frame_round_trips() {
+        let request = encode_request_frame(42, 7, Bytes::from_static(b"ping"));
+        assert_eq!(
+            decode_frame(BytesMut::from(&request[..])),
+            Some(DecodedFrame::Request { id: 42, msg_id: 7, payload: Bytes::from_static(b"ping") })
+        );
+
+        let response = encode_response_frame(42, Bytes::from_static(b"pong"));
+        assert_eq!(
+            decode_frame(BytesMut::from(&response[..])),
+            Some(DecodedFrame::Response { id: 42, payload: Bytes::from_static(b"pong") })
+        );
+    }
+
+    #[test]
+    fn request_id_generator_skips_ids_still_pending() {
+        let ids = RequestIdGenerator::default();
+        let mut pending = HashMap::new();
+        let (tx, _rx) = oneshot::channel();
+        let first = ids.next(&pending).expect("id available");
+        pending.insert(first, tx);
+
+        let second = ids.next(&pending).expect("id available");
+        assert_ne!(first, second, "must not hand out an id that's still pending");
+    }
+
+    #[test]
+    fn request_id_generator_exhausted_returns_none() {
+        let ids = RequestIdGenerator::default();
+        let mut pending = HashMap::new();
+        for id in 0..=u16::MAX {
+            let (tx, _rx) = oneshot::channel();
+            pending.insert(id, tx);
+        }
+        assert!(ids.next(&pending).is_none());
+    }
+
+    #[tokio::test]
+    async fn priority_send_loop_drains_high_priority_before_low() {
+        let sent = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let sink_sent = sent.clone();
+        let sink = futures::sink::unfold((), move |_, msg: Bytes| {
+            let sent = sink_sent.clone();
+            async move {
+                sent.lock().push(msg);
+                Ok::<_, std::convert::Infallible>(())
+            }
+        });
+
+        let (to_send, receiver) = mpsc::unbounded_channel();
+        let pending: PendingRequests = Arc::new(parking_lot::Mutex::new(HashMap::new()));
+        let send_loop =
+            PrioritySendLoop { sink: Box::pin(sink), receiver, pending, queues: HashMap::new() };
+
+        for (priority, msg) in [
+            (RequestPriority::Low, "low"),
+            (RequestPriority::High, "high"),
+            (RequestPriority::Normal, "normal"),
+        ] {
+            to_send.send(QueuedMessage { priority, msg: Bytes::from(msg) }).unwrap();
+        }
+        drop(to_send);
+
+        send_loop.run().await;
+
+        let sent = sent.lock();
+        assert_eq!(&*sent, &[Bytes::from("high"), Bytes::from("normal"), Bytes::from("low")]);
+    }
+
+    #[tokio::test]
+    async fn priority_send_loop_fails_pending_requests_when_channel_closes() {
+        let sink = futures::sink::unfold((), |_, _: Bytes| async {
+            Ok::<_, std::convert::Infallible>(())
+        });
+        let (to_send, receiver) = mpsc::unbounded_channel();
+        let pending: PendingRequests = Arc::new(parking_lot::Mutex::new(HashMap::new()));
+        let (tx, rx) = oneshot::channel();
+        pending.lock().insert(7, tx);
+
+        let send_loop =
+            PrioritySendLoop { sink: Box::pin(sink), receiver, pending, queues: HashMap::new() };
+        drop(to_send);
+        send_loop.run().await;
+
+        assert!(matches!(rx.await, Ok(Err(RequestResponseError::ConnectionClosed))));
+    }
+
+    #[tokio::test]
+    async fn connection_resolves_pending_on_response_frame() {
+        use futures::StreamExt;
+
+        let pending: PendingRequests = Arc::new(parking_lot::Mutex::new(HashMap::new()));
+        let (tx, rx) = oneshot::channel();
+        pending.lock().insert(5, tx);
+
+        let inbound = futures::stream::iter([BytesMut::from(
+            &encode_response_frame(5, Bytes::from_static(b"pong"))[..],
+        )]);
+        let (to_send, _receiver) = mpsc::unbounded_channel();
+        let mut conn = RequestResponseConnection::new(
+            Box::pin(inbound),
+            pending,
+            Arc::new(RequestHandlerRegistry::default()),
+            to_send,
+        );
+
+        assert_eq!(conn.next().await, Some(()));
+        assert_eq!(rx.await.unwrap().unwrap(), Bytes::from_static(b"pong"));
+    }
+
+    #[tokio::test]
+    async fn connection_dispatches_registered_handler_and_queues_reply() {
+        use futures::StreamExt;
+
+        let mut registry = RequestHandlerRegistry::default();
+        registry.insert(9, |payload: Bytes| async move {
+            let mut reply = BytesMut::from(&b"echo:"[..]);
+            reply.extend_from_slice(&payload);
+            reply.freeze()
+        });
+
+        let inbound = futures::stream::iter([BytesMut::from(
+            &encode_request_frame(3, 9, Bytes::from_static(b"hi"))[..],
+        )]);
+        let (to_send, mut receiver) = mpsc::unbounded_channel();
+        let mut conn = RequestResponseConnection::new(
+            Box::pin(inbound),
+            Arc::new(parking_lot::Mutex::new(HashMap::new())),
+            Arc::new(registry),
+            to_send,
+        );
+
+        assert_eq!(conn.next().await, Some(())); // processes the inbound request frame
+        assert_eq!(conn.next().await, None); // drains the handler's reply, then the stream ends
+
+        let queued = receiver.try_recv().expect("handler reply queued");
+        assert_eq!(queued.msg, encode_response_frame(3, Bytes::from_static(b"echo:hi")));
+    }
+}